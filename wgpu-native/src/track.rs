@@ -2,7 +2,7 @@ use crate::hub::{NewId, Id, Index, Epoch, Storage};
 use crate::resource::{BufferUsageFlags, TextureUsageFlags};
 use crate::{
     RefCount,
-    BufferId, TextureId, TextureViewId,
+    BufferId, TextureId, TextureViewId, SamplerId,
 };
 
 use bitflags::bitflags;
@@ -41,6 +41,25 @@ pub struct Query<T> {
     pub initialized: bool,
 }
 
+/// Why a tracker operation failed. Returned instead of panicking so that a
+/// command buffer built against a resource whose id has gone stale (its
+/// index recycled into something else) is rejected with an error, per the
+/// documented contract, rather than aborting the process.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackError<I, U> {
+    /// `id`'s index was reused by a different resource since `id` was
+    /// handed out; using it now would silently address whatever reused the
+    /// slot instead of reporting that the original resource is gone.
+    UseStaleResource {
+        index: Index,
+        expected_epoch: Epoch,
+        actual_epoch: Epoch,
+    },
+    /// The requested usage conflicts with `id`'s current usage, and neither
+    /// `EXTEND` nor `REPLACE` permits resolving it.
+    Conflicting { id: I, old: U, new: U },
+}
+
 bitflags! {
     pub struct TrackPermit: u32 {
         /// Allow extension of the current usage. This is useful during render pass
@@ -55,44 +74,543 @@ bitflags! {
 
 pub trait GenericUsage {
     fn is_exclusive(&self) -> bool;
+    /// True if every bit set in `self` belongs to the read-only subset that
+    /// hardware is free to reorder with respect to itself, meaning no
+    /// barrier is needed between two accesses that both satisfy this.
+    fn all_ordered(self) -> bool;
 }
 impl GenericUsage for BufferUsageFlags {
     fn is_exclusive(&self) -> bool {
         BufferUsageFlags::WRITE_ALL.intersects(*self)
     }
+    fn all_ordered(self) -> bool {
+        BufferUsageFlags::READ_ALL.contains(self)
+    }
 }
 impl GenericUsage for TextureUsageFlags {
     fn is_exclusive(&self) -> bool {
         TextureUsageFlags::WRITE_ALL.intersects(*self)
     }
+    fn all_ordered(self) -> bool {
+        TextureUsageFlags::READ_ALL.contains(self)
+    }
 }
 
-#[derive(Clone)]
-struct Track<U> {
-    ref_count: RefCount,
-    init: U,
-    last: U,
-    epoch: Epoch,
+/// Whether a transition from `old` to `new` usage needs a barrier at all:
+/// identical states never do, and neither do two non-exclusive states that
+/// are both in the freely-reorderable "ordered" subset (e.g. two flavors of
+/// read-only access).
+fn needs_transition<U: Copy + GenericUsage + PartialEq>(old: U, new: U) -> bool {
+    old != new && !(old.all_ordered() && new.all_ordered())
+}
+
+bitflags! {
+    /// Internal buffer usage bits the tracker reasons about, distinct from
+    /// the public `BufferUsageFlags`. Some states the tracker needs to tell
+    /// apart have no corresponding public bit: a storage buffer that's only
+    /// read from and one that's written to both just report the public
+    /// `STORAGE` bit, so a public-facing transition can't tell a read-only
+    /// access from a writable one.
+    pub struct BufferUse: u32 {
+        const MAP_READ = 1;
+        const MAP_WRITE = 2;
+        const COPY_SRC = 4;
+        const COPY_DST = 8;
+        const INDEX = 16;
+        const VERTEX = 32;
+        const UNIFORM = 64;
+        const STORAGE_LOAD = 128;
+        const STORAGE_STORE = 256;
+        const WRITE_ALL = Self::MAP_WRITE.bits | Self::COPY_DST.bits | Self::STORAGE_STORE.bits;
+        const READ_ALL = Self::MAP_READ.bits | Self::COPY_SRC.bits | Self::INDEX.bits
+            | Self::VERTEX.bits | Self::UNIFORM.bits | Self::STORAGE_LOAD.bits;
+    }
+}
+
+impl GenericUsage for BufferUse {
+    fn is_exclusive(&self) -> bool {
+        BufferUse::WRITE_ALL.intersects(*self)
+    }
+    fn all_ordered(self) -> bool {
+        BufferUse::READ_ALL.contains(self)
+    }
+}
+
+impl From<BufferUsageFlags> for BufferUse {
+    fn from(public: BufferUsageFlags) -> Self {
+        let mut internal = BufferUse::empty();
+        internal.set(BufferUse::MAP_READ, public.contains(BufferUsageFlags::MAP_READ));
+        internal.set(BufferUse::MAP_WRITE, public.contains(BufferUsageFlags::MAP_WRITE));
+        internal.set(BufferUse::COPY_SRC, public.contains(BufferUsageFlags::COPY_SRC));
+        internal.set(BufferUse::COPY_DST, public.contains(BufferUsageFlags::COPY_DST));
+        internal.set(BufferUse::INDEX, public.contains(BufferUsageFlags::INDEX));
+        internal.set(BufferUse::VERTEX, public.contains(BufferUsageFlags::VERTEX));
+        internal.set(BufferUse::UNIFORM, public.contains(BufferUsageFlags::UNIFORM));
+        if public.contains(BufferUsageFlags::STORAGE) {
+            // The public bit can't tell a read-only storage binding from a
+            // read-write one, so conservatively expand to both.
+            internal.insert(BufferUse::STORAGE_LOAD | BufferUse::STORAGE_STORE);
+        }
+        internal
+    }
+}
+
+bitflags! {
+    /// Internal texture usage bits, mirroring `BufferUse`'s split of the
+    /// public `STORAGE` bit into separate load/store bits so the tracker can
+    /// tell a texture that's only sampled from one that's written to via a
+    /// storage binding.
+    pub struct TextureUse: u32 {
+        const COPY_SRC = 1;
+        const COPY_DST = 2;
+        const SAMPLED = 4;
+        const STORAGE_LOAD = 8;
+        const STORAGE_STORE = 16;
+        const COLOR_TARGET = 32;
+        const DEPTH_STENCIL_READ = 64;
+        const DEPTH_STENCIL_WRITE = 128;
+        const WRITE_ALL = Self::COPY_DST.bits | Self::STORAGE_STORE.bits
+            | Self::COLOR_TARGET.bits | Self::DEPTH_STENCIL_WRITE.bits;
+        const READ_ALL = Self::COPY_SRC.bits | Self::SAMPLED.bits
+            | Self::STORAGE_LOAD.bits | Self::DEPTH_STENCIL_READ.bits;
+    }
+}
+
+impl GenericUsage for TextureUse {
+    fn is_exclusive(&self) -> bool {
+        TextureUse::WRITE_ALL.intersects(*self)
+    }
+    fn all_ordered(self) -> bool {
+        TextureUse::READ_ALL.contains(self)
+    }
+}
+
+impl From<TextureUsageFlags> for TextureUse {
+    fn from(public: TextureUsageFlags) -> Self {
+        let mut internal = TextureUse::empty();
+        internal.set(TextureUse::COPY_SRC, public.contains(TextureUsageFlags::COPY_SRC));
+        internal.set(TextureUse::COPY_DST, public.contains(TextureUsageFlags::COPY_DST));
+        internal.set(TextureUse::SAMPLED, public.contains(TextureUsageFlags::SAMPLED));
+        if public.contains(TextureUsageFlags::STORAGE) {
+            internal.insert(TextureUse::STORAGE_LOAD | TextureUse::STORAGE_STORE);
+        }
+        if public.contains(TextureUsageFlags::OUTPUT_ATTACHMENT) {
+            // Could be a color or depth/stencil attachment, and either read
+            // or written to (e.g. with blending or a depth test); expand to
+            // every attachment bit rather than guessing.
+            internal.insert(
+                TextureUse::COLOR_TARGET
+                    | TextureUse::DEPTH_STENCIL_READ
+                    | TextureUse::DEPTH_STENCIL_WRITE,
+            );
+        }
+        internal
+    }
+}
+
+/// A mip level, zero-based.
+pub type Level = u32;
+/// An array layer, zero-based.
+pub type Layer = u32;
+
+/// A rectangular range of subresources within a texture: a range of mip
+/// levels crossed with a range of array layers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureSelector {
+    pub levels: Range<Level>,
+    pub layers: Range<Layer>,
+}
+
+impl TextureSelector {
+    /// Decompose into linear subresource-index ranges against a resource
+    /// with `level_count` mip levels total. A selector spanning the full
+    /// level range collapses into a single contiguous range (the common,
+    /// whole-texture-ish case); anything narrower is walked one array layer
+    /// at a time, since a sub-range of levels isn't contiguous across
+    /// layers in the linearized index.
+    fn linearize(&self, level_count: Level) -> Vec<Range<u32>> {
+        if self.levels.start == 0 && self.levels.end == level_count {
+            vec![self.layers.start * level_count..self.layers.end * level_count]
+        } else {
+            self.layers
+                .clone()
+                .map(|layer| {
+                    layer * level_count + self.levels.start..layer * level_count + self.levels.end
+                })
+                .collect()
+        }
+    }
+}
+
+/// A sorted, non-overlapping list of `(subresource index range, state)`
+/// segments, used to track per-subresource state along a single linearized
+/// axis.
+///
+/// Segments are kept sorted by their range and coalesced whenever adjacent
+/// segments carry equal state, so the vector stays as compact as the actual
+/// diversity of state in the resource.
+#[derive(Clone, Debug)]
+struct RangedStates<T> {
+    ranges: Vec<(Range<u32>, T)>,
+}
+
+impl<T: Copy + PartialEq> RangedStates<T> {
+    fn from_range(range: Range<u32>, value: T) -> Self {
+        RangedStates {
+            ranges: vec![(range, value)],
+        }
+    }
+
+    /// Split the segments so that `index_range`'s boundaries line up exactly
+    /// with segment boundaries (growing the tracked range with `default` if
+    /// `index_range` pokes outside of it), then return the now-contiguous
+    /// slice of segments covering `index_range`.
+    ///
+    /// `index_range` must be non-empty: an empty range has no boundary to
+    /// line up segments against, and the callers below skip it instead.
+    fn isolate(&mut self, index_range: &Range<u32>, default: T) -> &mut [(Range<u32>, T)] {
+        debug_assert!(!index_range.is_empty());
+        if self.ranges.is_empty() {
+            self.ranges.push((index_range.clone(), default));
+        }
+        if index_range.start < self.ranges.first().unwrap().0.start {
+            let end = self.ranges.first().unwrap().0.start;
+            self.ranges.insert(0, (index_range.start..end, default));
+        }
+        if index_range.end > self.ranges.last().unwrap().0.end {
+            let start = self.ranges.last().unwrap().0.end;
+            self.ranges.push((start..index_range.end, default));
+        }
+
+        let start_pos = self
+            .ranges
+            .iter()
+            .position(|(r, _)| r.end > index_range.start)
+            .unwrap();
+        if self.ranges[start_pos].0.start < index_range.start {
+            let (range, value) = self.ranges[start_pos].clone();
+            self.ranges[start_pos].0 = index_range.start..range.end;
+            self.ranges
+                .insert(start_pos, (range.start..index_range.start, value));
+        }
+
+        let end_pos = self
+            .ranges
+            .iter()
+            .position(|(r, _)| r.end >= index_range.end)
+            .unwrap();
+        if self.ranges[end_pos].0.end > index_range.end {
+            let (range, value) = self.ranges[end_pos].clone();
+            self.ranges[end_pos].0 = range.start..index_range.end;
+            self.ranges
+                .insert(end_pos + 1, (index_range.end..range.end, value));
+        }
+
+        let start_pos = self
+            .ranges
+            .iter()
+            .position(|(r, _)| r.start == index_range.start)
+            .unwrap();
+        let end_pos = self
+            .ranges
+            .iter()
+            .position(|(r, _)| r.end == index_range.end)
+            .unwrap();
+        &mut self.ranges[start_pos..=end_pos]
+    }
+
+    /// Merge adjacent segments that carry equal state.
+    fn coalesce(&mut self) {
+        let mut i = 1;
+        while i < self.ranges.len() {
+            if self.ranges[i - 1].0.end == self.ranges[i].0.start
+                && self.ranges[i - 1].1 == self.ranges[i].1
+            {
+                self.ranges[i - 1].0.end = self.ranges[i].0.end;
+                self.ranges.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// A word-packed occupancy bitset, indexed directly by `Index` rather than
+/// hashed, so testing and clearing membership is a shift-and-mask instead of
+/// a hash + probe.
+#[derive(Clone, Default)]
+struct Bitset {
+    words: Vec<u64>,
+}
+
+impl Bitset {
+    fn contains(&self, index: Index) -> bool {
+        let index = index as usize;
+        let word = index / 64;
+        word < self.words.len() && self.words[word] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: Index) {
+        let index = index as usize;
+        let word = index / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (index % 64);
+    }
+
+    /// Clear the bit, returning whether it was set.
+    fn clear(&mut self, index: Index) -> bool {
+        let index = index as usize;
+        let word = index / 64;
+        if word >= self.words.len() {
+            return false;
+        }
+        let mask = 1 << (index % 64);
+        let was_set = self.words[word] & mask != 0;
+        self.words[word] &= !mask;
+        was_set
+    }
+
+    /// Iterate the set bit indices in ascending order, a word at a time.
+    fn iter<'a>(&'a self) -> impl 'a + Iterator<Item = Index> {
+        self.words.iter().enumerate().flat_map(|(word_index, &word)| {
+            let mut remaining = word;
+            std::iter::from_fn(move || {
+                if remaining == 0 {
+                    None
+                } else {
+                    let bit = remaining.trailing_zeros();
+                    remaining &= remaining - 1;
+                    Some((word_index * 64) as Index + bit as Index)
+                }
+            })
+        })
+    }
 }
 
 //TODO: consider having `I` as an associated type of `U`?
+///
+/// Backed by flat vectors indexed directly by `Index` instead of a
+/// `FastHashMap`, since command-buffer recording hits this on the hot path
+/// and hashing/pointer-chasing for every resource access adds up. The
+/// vectors grow to fit the largest index seen; `bitset` tracks which slots
+/// are actually occupied. Once `bitset`/`ensure_len` establish that an index
+/// is in bounds, reads go through `get_unchecked` behind a `debug_assert!`,
+/// so a release build pays for the epoch comparison but not a second bounds
+/// check on top of it.
 pub struct Tracker<I, U> {
-    map: FastHashMap<Index, Track<U>>,
+    epochs: Vec<Epoch>,
+    ref_counts: Vec<Option<RefCount>>,
+    init: Vec<U>,
+    last: Vec<U>,
+    bitset: Bitset,
     _phantom: PhantomData<I>,
 }
-pub type BufferTracker = Tracker<BufferId, BufferUsageFlags>;
-pub type TextureTracker = Tracker<TextureId, TextureUsageFlags>;
-pub struct DummyTracker<I> {
-    map: FastHashMap<Index, (RefCount, Epoch)>,
+pub type BufferTracker = Tracker<BufferId, BufferUse>;
+
+/// Same flat-vector, bitset-indexed layout as `Tracker`, for resources that
+/// have no usage state but still need their `RefCount` kept alive and their
+/// epoch validated.
+pub struct StatelessTracker<I> {
+    epochs: Vec<Epoch>,
+    ref_counts: Vec<Option<RefCount>>,
+    bitset: Bitset,
     _phantom: PhantomData<I>,
 }
-pub type TextureViewTracker = DummyTracker<TextureViewId>;
+pub type TextureViewTracker = StatelessTracker<TextureViewId>;
+pub type SamplerTracker = StatelessTracker<SamplerId>;
+/// Shared by bind groups to keep every resource they reference alive
+/// (holding a `RefCount` per entry) without caring about usage state or id
+/// kind.
+pub type BindGroupTracker = StatelessTracker<Id>;
+
+/// Per-subresource tracking state for a single texture: the usage carried by
+/// each (layer, level) pair, stored as ranges over the subresource index
+/// `layer * level_count + level`. A subresource that has never been touched
+/// is `None`, distinct from one that's carrying an actual (possibly empty)
+/// usage, so a first access can be reported as `initialized: true` without
+/// having to assume anything about subresources outside the selector.
+#[derive(Clone)]
+struct TextureTrack {
+    ref_count: RefCount,
+    epoch: Epoch,
+    /// Number of mip levels in the full resource, fixed when the track is
+    /// created from the caller-supplied full extent, used to linearize
+    /// `(layer, level)` into a subresource index.
+    level_count: Level,
+    state: RangedStates<Option<TextureUse>>,
+}
+
+pub struct TextureTracker {
+    map: FastHashMap<Index, TextureTrack>,
+}
+
+impl TextureTracker {
+    pub fn new() -> Self {
+        TextureTracker {
+            map: FastHashMap::default(),
+        }
+    }
+
+    /// Remove an id from the tracked map.
+    pub(crate) fn remove(&mut self, id: TextureId) -> Result<bool, TrackError<TextureId, TextureUse>> {
+        match self.map.remove(&id.index()) {
+            Some(track) => {
+                if track.epoch != id.epoch() {
+                    return Err(TrackError::UseStaleResource {
+                        index: id.index(),
+                        expected_epoch: id.epoch(),
+                        actual_epoch: track.epoch,
+                    });
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Get the last usage on the subresources covered by `selector`, within a
+    /// texture whose full extent is `full_range`. Subresources outside
+    /// `selector` are left untouched; a subresource that's never been
+    /// accessed before is reported with `initialized: true` and seeded with
+    /// `default`, while one that already carries a usage is reported as-is
+    /// with `initialized: false`.
+    pub(crate) fn query(
+        &mut self,
+        id: TextureId,
+        ref_count: &RefCount,
+        full_range: TextureSelector,
+        selector: TextureSelector,
+        default: impl Into<TextureUse>,
+    ) -> Result<Vec<(Range<u32>, Query<TextureUse>)>, TrackError<TextureId, TextureUse>> {
+        let default = default.into();
+        let track = match self.map.entry(id.index()) {
+            Entry::Vacant(e) => {
+                let level_count = full_range.levels.end;
+                let total = full_range.layers.end * level_count;
+                e.insert(TextureTrack {
+                    ref_count: ref_count.clone(),
+                    epoch: id.epoch(),
+                    level_count,
+                    state: RangedStates::from_range(0..total, None),
+                })
+            }
+            Entry::Occupied(e) => e.into_mut(),
+        };
+        if track.epoch != id.epoch() {
+            return Err(TrackError::UseStaleResource {
+                index: id.index(),
+                expected_epoch: id.epoch(),
+                actual_epoch: track.epoch,
+            });
+        }
+
+        let mut result = Vec::new();
+        for layer_range in selector.linearize(track.level_count) {
+            // An empty selector (e.g. a zero-length layer range) touches no
+            // subresources; `isolate` requires a non-empty range to split on.
+            if layer_range.is_empty() {
+                continue;
+            }
+            for &mut (ref range, ref mut slot) in track.state.isolate(&layer_range, None) {
+                let initialized = slot.is_none();
+                let usage = slot.unwrap_or(default);
+                *slot = Some(usage);
+                result.push((range.clone(), Query { usage, initialized }));
+            }
+        }
+        track.state.coalesce();
+        Ok(result)
+    }
+
+    /// Transit the subresources covered by `selector`, within a texture
+    /// whose full extent is `full_range`, into `usage`. Returns one
+    /// `(subresource index range, old..new usage)` pair per affected segment
+    /// that needs a barrier, so the caller can emit a barrier per distinct
+    /// subresource range. Segments touched for the first time, segments
+    /// already in `usage`, and extensions between two ordered usages yield
+    /// no pair.
+    pub(crate) fn transit(
+        &mut self,
+        id: TextureId,
+        ref_count: &RefCount,
+        full_range: TextureSelector,
+        selector: TextureSelector,
+        usage: impl Into<TextureUse>,
+        permit: TrackPermit,
+    ) -> Result<Vec<(Range<u32>, Range<TextureUse>)>, TrackError<TextureId, TextureUse>> {
+        let usage = usage.into();
+        let track = match self.map.entry(id.index()) {
+            Entry::Vacant(e) => {
+                let level_count = full_range.levels.end;
+                let total = full_range.layers.end * level_count;
+                e.insert(TextureTrack {
+                    ref_count: ref_count.clone(),
+                    epoch: id.epoch(),
+                    level_count,
+                    state: RangedStates::from_range(0..total, None),
+                })
+            }
+            Entry::Occupied(e) => e.into_mut(),
+        };
+        if track.epoch != id.epoch() {
+            return Err(TrackError::UseStaleResource {
+                index: id.index(),
+                expected_epoch: id.epoch(),
+                actual_epoch: track.epoch,
+            });
+        }
+
+        let mut transitions = Vec::new();
+        for layer_range in selector.linearize(track.level_count) {
+            // An empty selector (e.g. a zero-length layer range) touches no
+            // subresources; `isolate` requires a non-empty range to split on.
+            if layer_range.is_empty() {
+                continue;
+            }
+            for &mut (ref range, ref mut slot) in track.state.isolate(&layer_range, None) {
+                match *slot {
+                    None => {
+                        *slot = Some(usage);
+                    }
+                    Some(old_usage) => {
+                        if usage == old_usage {
+                            continue;
+                        } else if permit.contains(TrackPermit::EXTEND)
+                            && !(old_usage | usage).is_exclusive()
+                        {
+                            *slot = Some(old_usage | usage);
+                            if needs_transition(old_usage, usage) {
+                                transitions.push((range.clone(), old_usage..usage));
+                            }
+                        } else if permit.contains(TrackPermit::REPLACE) {
+                            *slot = Some(usage);
+                            transitions.push((range.clone(), old_usage..usage));
+                        } else {
+                            return Err(TrackError::Conflicting { id, old: old_usage, new: usage });
+                        }
+                    }
+                }
+            }
+        }
+        track.state.coalesce();
+        Ok(transitions)
+    }
+
+    /// Return an iterator over used resource keys, ignoring subresource detail.
+    pub fn used<'a>(&'a self) -> impl 'a + Iterator<Item = TextureId> {
+        self.map
+            .iter()
+            .map(|(&index, track)| TextureId::new(index, track.epoch))
+    }
+}
 
 pub struct TrackerSet {
     pub buffers: BufferTracker,
     pub textures: TextureTracker,
     pub views: TextureViewTracker,
-    //TODO: samplers
+    pub samplers: SamplerTracker,
 }
 
 impl TrackerSet {
@@ -101,92 +619,165 @@ impl TrackerSet {
             buffers: BufferTracker::new(),
             textures: TextureTracker::new(),
             views: TextureViewTracker::new(),
+            samplers: SamplerTracker::new(),
         }
     }
 }
 
-impl<I: NewId> DummyTracker<I> {
+impl<I: NewId> StatelessTracker<I> {
     pub fn new() -> Self {
-        DummyTracker {
-            map: FastHashMap::default(),
+        StatelessTracker {
+            epochs: Vec::new(),
+            ref_counts: Vec::new(),
+            bitset: Bitset::default(),
             _phantom: PhantomData,
         }
     }
 
-    /// Remove an id from the tracked map.
-    pub(crate) fn remove(&mut self, id: I) -> bool {
-        match self.map.remove(&id.index()) {
-            Some((_, epoch)) => {
-                assert_eq!(epoch, id.epoch());
-                true
-            }
-            None => false,
+    fn ensure_len(&mut self, index: usize) {
+        if self.epochs.len() <= index {
+            let new_len = index + 1;
+            self.epochs.resize(new_len, 0);
+            self.ref_counts.resize(new_len, None);
+        }
+    }
+
+    /// Remove an id from the tracked set.
+    pub(crate) fn remove(&mut self, id: I) -> Result<bool, TrackError<I, ()>> {
+        if !self.bitset.clear(id.index()) {
+            return Ok(false);
         }
+        let index = id.index() as usize;
+        // `bitset.clear` only returned `true` because `index` was set, which
+        // only happens after `ensure_len` has grown `epochs` past it.
+        debug_assert!(index < self.epochs.len());
+        let epoch = unsafe { *self.epochs.get_unchecked(index) };
+        if epoch != id.epoch() {
+            return Err(TrackError::UseStaleResource {
+                index: id.index(),
+                expected_epoch: id.epoch(),
+                actual_epoch: epoch,
+            });
+        }
+        self.ref_counts[index] = None;
+        Ok(true)
     }
 
     /// Get the last usage on a resource.
-    pub(crate) fn query(&mut self, id: I, ref_count: &RefCount) -> bool {
-        match self.map.entry(id.index()) {
-            Entry::Vacant(e) => {
-                e.insert((ref_count.clone(), id.epoch()));
-                true
-            }
-            Entry::Occupied(e) => {
-                assert_eq!(e.get().1, id.epoch());
-                false
+    pub(crate) fn query(&mut self, id: I, ref_count: &RefCount) -> Result<bool, TrackError<I, ()>> {
+        let index = id.index() as usize;
+        self.ensure_len(index);
+        if self.bitset.contains(id.index()) {
+            debug_assert!(index < self.epochs.len());
+            let epoch = unsafe { *self.epochs.get_unchecked(index) };
+            if epoch != id.epoch() {
+                return Err(TrackError::UseStaleResource {
+                    index: id.index(),
+                    expected_epoch: id.epoch(),
+                    actual_epoch: epoch,
+                });
             }
+            Ok(false)
+        } else {
+            self.bitset.set(id.index());
+            self.epochs[index] = id.epoch();
+            self.ref_counts[index] = Some(ref_count.clone());
+            Ok(true)
         }
     }
 
     /// Consume another tacker.
-    pub fn consume(&mut self, other: &Self) {
-        for (&index, &(ref ref_count, epoch)) in &other.map {
-            self.query(I::new(index, epoch), ref_count);
+    pub fn consume(&mut self, other: &Self) -> Result<(), TrackError<I, ()>> {
+        for index in other.bitset.iter() {
+            let idx = index as usize;
+            debug_assert!(idx < other.epochs.len());
+            let epoch = unsafe { *other.epochs.get_unchecked(idx) };
+            debug_assert!(idx < other.ref_counts.len());
+            let ref_count = unsafe { other.ref_counts.get_unchecked(idx) }.as_ref().unwrap();
+            self.query(I::new(index, epoch), ref_count)?;
         }
+        Ok(())
     }
 }
 
-impl<I: NewId, U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<I, U> {
+impl<I: NewId, U: Copy + GenericUsage + BitOr<Output = U> + PartialEq + Default> Tracker<I, U> {
     pub fn new() -> Self {
         Tracker {
-            map: FastHashMap::default(),
+            epochs: Vec::new(),
+            ref_counts: Vec::new(),
+            init: Vec::new(),
+            last: Vec::new(),
+            bitset: Bitset::default(),
             _phantom: PhantomData,
         }
     }
 
-    /// Remove an id from the tracked map.
-    pub(crate) fn remove(&mut self, id: I) -> bool {
-        match self.map.remove(&id.index()) {
-            Some(track) => {
-                assert_eq!(track.epoch, id.epoch());
-                true
-            }
-            None => false,
+    fn ensure_len(&mut self, index: usize) {
+        if self.epochs.len() <= index {
+            let new_len = index + 1;
+            self.epochs.resize(new_len, 0);
+            self.ref_counts.resize(new_len, None);
+            self.init.resize(new_len, U::default());
+            self.last.resize(new_len, U::default());
         }
     }
 
+    /// Remove an id from the tracked set.
+    pub(crate) fn remove(&mut self, id: I) -> Result<bool, TrackError<I, U>> {
+        if !self.bitset.clear(id.index()) {
+            return Ok(false);
+        }
+        let index = id.index() as usize;
+        // `bitset.clear` only returned `true` because `index` was set, which
+        // only happens after `ensure_len` has grown the vectors past it.
+        debug_assert!(index < self.epochs.len());
+        let epoch = unsafe { *self.epochs.get_unchecked(index) };
+        if epoch != id.epoch() {
+            return Err(TrackError::UseStaleResource {
+                index: id.index(),
+                expected_epoch: id.epoch(),
+                actual_epoch: epoch,
+            });
+        }
+        self.ref_counts[index] = None;
+        Ok(true)
+    }
+
     /// Get the last usage on a resource.
-    pub(crate) fn query(&mut self, id: I, ref_count: &RefCount, default: U) -> Query<U> {
-        match self.map.entry(id.index()) {
-            Entry::Vacant(e) => {
-                e.insert(Track {
-                    ref_count: ref_count.clone(),
-                    init: default,
-                    last: default,
-                    epoch: id.epoch(),
+    pub(crate) fn query(
+        &mut self,
+        id: I,
+        ref_count: &RefCount,
+        default: impl Into<U>,
+    ) -> Result<Query<U>, TrackError<I, U>> {
+        let default = default.into();
+        let index = id.index() as usize;
+        self.ensure_len(index);
+        if self.bitset.contains(id.index()) {
+            debug_assert!(index < self.epochs.len());
+            let epoch = unsafe { *self.epochs.get_unchecked(index) };
+            if epoch != id.epoch() {
+                return Err(TrackError::UseStaleResource {
+                    index: id.index(),
+                    expected_epoch: id.epoch(),
+                    actual_epoch: epoch,
                 });
-                Query {
-                    usage: default,
-                    initialized: true,
-                }
-            }
-            Entry::Occupied(e) => {
-                assert_eq!(e.get().epoch, id.epoch());
-                Query {
-                    usage: e.get().last,
-                    initialized: false,
-                }
             }
+            debug_assert!(index < self.last.len());
+            Ok(Query {
+                usage: unsafe { *self.last.get_unchecked(index) },
+                initialized: false,
+            })
+        } else {
+            self.bitset.set(id.index());
+            self.epochs[index] = id.epoch();
+            self.ref_counts[index] = Some(ref_count.clone());
+            self.init[index] = default;
+            self.last[index] = default;
+            Ok(Query {
+                usage: default,
+                initialized: true,
+            })
         }
     }
 
@@ -195,79 +786,133 @@ impl<I: NewId, U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<I
         &mut self,
         id: I,
         ref_count: &RefCount,
-        usage: U,
+        usage: impl Into<U>,
         permit: TrackPermit,
-    ) -> Result<Tracktion<U>, U> {
-        match self.map.entry(id.index()) {
-            Entry::Vacant(e) => {
-                e.insert(Track {
-                    ref_count: ref_count.clone(),
-                    init: usage,
-                    last: usage,
-                    epoch: id.epoch(),
-                });
-                Ok(Tracktion::Init)
-            }
-            Entry::Occupied(mut e) => {
-                assert_eq!(e.get().epoch, id.epoch());
-                let old = e.get().last;
-                if usage == old {
-                    Ok(Tracktion::Keep)
-                } else if permit.contains(TrackPermit::EXTEND) && !(old | usage).is_exclusive() {
-                    e.get_mut().last = old | usage;
-                    Ok(Tracktion::Extend { old })
-                } else if permit.contains(TrackPermit::REPLACE) {
-                    e.get_mut().last = usage;
-                    Ok(Tracktion::Replace { old })
-                } else {
-                    Err(old)
-                }
+    ) -> Result<Tracktion<U>, TrackError<I, U>> {
+        let usage = usage.into();
+        let index = id.index() as usize;
+        self.ensure_len(index);
+        if !self.bitset.contains(id.index()) {
+            self.bitset.set(id.index());
+            self.epochs[index] = id.epoch();
+            self.ref_counts[index] = Some(ref_count.clone());
+            self.init[index] = usage;
+            self.last[index] = usage;
+            return Ok(Tracktion::Init);
+        }
+        debug_assert!(index < self.epochs.len());
+        let epoch = unsafe { *self.epochs.get_unchecked(index) };
+        if epoch != id.epoch() {
+            return Err(TrackError::UseStaleResource {
+                index: id.index(),
+                expected_epoch: id.epoch(),
+                actual_epoch: epoch,
+            });
+        }
+        debug_assert!(index < self.last.len());
+        let old = unsafe { *self.last.get_unchecked(index) };
+        if usage == old {
+            Ok(Tracktion::Keep)
+        } else if permit.contains(TrackPermit::EXTEND) && !(old | usage).is_exclusive() {
+            self.last[index] = old | usage;
+            if needs_transition(old, usage) {
+                Ok(Tracktion::Extend { old })
+            } else {
+                // Two freely-reorderable read-only usages: the state is
+                // still extended for future exclusivity checks, but no
+                // barrier needs to be emitted for this access.
+                Ok(Tracktion::Keep)
             }
+        } else if permit.contains(TrackPermit::REPLACE) {
+            self.last[index] = usage;
+            Ok(Tracktion::Replace { old })
+        } else {
+            Err(TrackError::Conflicting { id, old, new: usage })
         }
     }
 
     /// Consume another tacker, adding it's transitions to `self`.
     /// Transitions the current usage to the new one.
-    pub fn consume_by_replace<'a>(&'a mut self, other: &'a Self) -> impl 'a + Iterator<Item = (I, Range<U>)> {
-        other.map.iter().flat_map(move |(&index, new)| {
-            match self.map.entry(index) {
-                Entry::Vacant(e) => {
-                    e.insert(new.clone());
-                    None
+    pub fn consume_by_replace<'a>(
+        &'a mut self,
+        other: &'a Self,
+    ) -> impl 'a + Iterator<Item = Result<(I, Range<U>), TrackError<I, U>>> {
+        other.bitset.iter().filter_map(move |index| {
+            let idx = index as usize;
+            self.ensure_len(idx);
+            debug_assert!(idx < other.epochs.len());
+            let new_epoch = unsafe { *other.epochs.get_unchecked(idx) };
+            debug_assert!(idx < other.init.len());
+            let new_init = unsafe { *other.init.get_unchecked(idx) };
+            debug_assert!(idx < other.last.len());
+            let new_last = unsafe { *other.last.get_unchecked(idx) };
+            if self.bitset.contains(index) {
+                debug_assert!(idx < self.epochs.len());
+                let cur_epoch = unsafe { *self.epochs.get_unchecked(idx) };
+                if cur_epoch != new_epoch {
+                    return Some(Err(TrackError::UseStaleResource {
+                        index,
+                        expected_epoch: new_epoch,
+                        actual_epoch: cur_epoch,
+                    }));
                 }
-                Entry::Occupied(mut e) => {
-                    assert_eq!(e.get().epoch, new.epoch);
-                    let old = mem::replace(&mut e.get_mut().last, new.last);
-                    if old == new.init {
-                        None
-                    } else {
-                        Some((I::new(index, new.epoch), old .. new.last))
-                    }
+                debug_assert!(idx < self.last.len());
+                let old = mem::replace(unsafe { self.last.get_unchecked_mut(idx) }, new_last);
+                if old == new_init || !needs_transition(old, new_last) {
+                    None
+                } else {
+                    Some(Ok((I::new(index, new_epoch), old..new_last)))
                 }
+            } else {
+                self.bitset.set(index);
+                self.epochs[idx] = new_epoch;
+                self.ref_counts[idx] = other.ref_counts[idx].clone();
+                self.init[idx] = new_init;
+                self.last[idx] = new_last;
+                None
             }
         })
     }
 
     /// Consume another tacker, adding it's transitions to `self`.
     /// Extends the current usage without doing any transitions.
-    pub fn consume_by_extend<'a>(&'a mut self, other: &'a Self) -> Result<(), (I, Range<U>)> {
-        for (&index, new) in other.map.iter() {
-            match self.map.entry(index) {
-                Entry::Vacant(e) => {
-                    e.insert(new.clone());
+    pub fn consume_by_extend<'a>(&'a mut self, other: &'a Self) -> Result<(), TrackError<I, U>> {
+        for index in other.bitset.iter() {
+            let idx = index as usize;
+            self.ensure_len(idx);
+            debug_assert!(idx < other.epochs.len());
+            let new_epoch = unsafe { *other.epochs.get_unchecked(idx) };
+            debug_assert!(idx < other.last.len());
+            let new_last = unsafe { *other.last.get_unchecked(idx) };
+            if self.bitset.contains(index) {
+                debug_assert!(idx < self.epochs.len());
+                let cur_epoch = unsafe { *self.epochs.get_unchecked(idx) };
+                if cur_epoch != new_epoch {
+                    return Err(TrackError::UseStaleResource {
+                        index,
+                        expected_epoch: new_epoch,
+                        actual_epoch: cur_epoch,
+                    });
                 }
-                Entry::Occupied(mut e) => {
-                    assert_eq!(e.get().epoch, new.epoch);
-                    let old = e.get().last;
-                    if old != new.last {
-                        let extended = old | new.last;
-                        if extended.is_exclusive() {
-                            let id = I::new(index, new.epoch);
-                            return Err((id, old .. new.last));
-                        }
-                        e.get_mut().last = extended;
+                debug_assert!(idx < self.last.len());
+                let old = unsafe { *self.last.get_unchecked(idx) };
+                if old != new_last {
+                    let extended = old | new_last;
+                    if extended.is_exclusive() {
+                        return Err(TrackError::Conflicting {
+                            id: I::new(index, new_epoch),
+                            old,
+                            new: new_last,
+                        });
                     }
+                    self.last[idx] = extended;
                 }
+            } else {
+                self.bitset.set(index);
+                self.epochs[idx] = new_epoch;
+                self.ref_counts[idx] = other.ref_counts[idx].clone();
+                self.init[idx] = other.init[idx];
+                self.last[idx] = new_last;
             }
         }
         Ok(())
@@ -275,18 +920,22 @@ impl<I: NewId, U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<I
 
     /// Return an iterator over used resources keys.
     pub fn used<'a>(&'a self) -> impl 'a + Iterator<Item = I> {
-        self.map.iter().map(|(&index, track)| I::new(index, track.epoch))
+        self.bitset.iter().map(move |index| {
+            let idx = index as usize;
+            debug_assert!(idx < self.epochs.len());
+            I::new(index, unsafe { *self.epochs.get_unchecked(idx) })
+        })
     }
 }
 
-impl<U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<Id, U> {
+impl<U: Copy + GenericUsage + BitOr<Output = U> + PartialEq + Default> Tracker<Id, U> {
     fn _get_with_usage<'a, T: 'a + Borrow<RefCount>>(
         &mut self,
         storage: &'a Storage<T>,
         id: Id,
-        usage: U,
+        usage: impl Into<U>,
         permit: TrackPermit,
-    ) -> Result<(&'a T, Tracktion<U>), U> {
+    ) -> Result<(&'a T, Tracktion<U>), TrackError<Id, U>> {
         let item = storage.get(id);
         self.transit(id, item.borrow(), usage, permit)
             .map(|tracktion| (item, tracktion))
@@ -296,8 +945,8 @@ impl<U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<Id, U> {
         &mut self,
         storage: &'a Storage<T>,
         id: Id,
-        usage: U,
-    ) -> Result<&'a T, U> {
+        usage: impl Into<U>,
+    ) -> Result<&'a T, TrackError<Id, U>> {
         let item = storage.get(id);
         self.transit(id, item.borrow(), usage, TrackPermit::EXTEND)
             .map(|_tracktion| item)
@@ -307,8 +956,8 @@ impl<U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<Id, U> {
         &mut self,
         storage: &'a Storage<T>,
         id: Id,
-        usage: U,
-    ) -> Result<(&'a T, Option<U>), U> {
+        usage: impl Into<U>,
+    ) -> Result<(&'a T, Option<U>), TrackError<Id, U>> {
         let item = storage.get(id);
         self.transit(id, item.borrow(), usage, TrackPermit::REPLACE)
             .map(|tracktion| (item, match tracktion {
@@ -318,4 +967,106 @@ impl<U: Copy + GenericUsage + BitOr<Output = U> + PartialEq> Tracker<Id, U> {
                 Tracktion::Replace { old } => Some(old),
             }))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranged_states_isolate_splits_a_middle_segment() {
+        let mut states = RangedStates::from_range(0..10, 0u32);
+        for seg in states.isolate(&(3..7), 0) {
+            seg.1 = 1;
+        }
+        assert_eq!(states.ranges, vec![(0..3, 0), (3..7, 1), (7..10, 0)]);
+    }
+
+    #[test]
+    fn ranged_states_isolate_grows_past_both_ends() {
+        let mut states = RangedStates::from_range(4..6, 9u32);
+        let segs = states.isolate(&(0..10), 9);
+        assert_eq!(segs.len(), 3);
+        assert_eq!(states.ranges, vec![(0..4, 9), (4..6, 9), (6..10, 9)]);
+    }
+
+    #[test]
+    fn ranged_states_isolate_overlapping_selector_splits_existing_segment() {
+        let mut states = RangedStates::from_range(0..10, 0u32);
+        for seg in states.isolate(&(2..5), 0) {
+            seg.1 = 1;
+        }
+        // [4..8) overlaps the tail of the [2..5) segment we just wrote and
+        // extends past it into the untouched [5..10) segment.
+        for seg in states.isolate(&(4..8), 0) {
+            seg.1 = 2;
+        }
+        // `isolate` only splits segments to line up with the boundary; it
+        // takes a `coalesce()` call to merge the now-equal (4..5, 2) and
+        // (5..8, 2) segments it left behind.
+        states.coalesce();
+        assert_eq!(
+            states.ranges,
+            vec![(0..2, 0), (2..4, 1), (4..8, 2), (8..10, 0)],
+        );
+    }
+
+    #[test]
+    fn ranged_states_isolate_adjacent_selector_does_not_disturb_neighbor() {
+        let mut states = RangedStates::from_range(0..10, 0u32);
+        for seg in states.isolate(&(0..5), 0) {
+            seg.1 = 1;
+        }
+        for seg in states.isolate(&(5..10), 0) {
+            seg.1 = 2;
+        }
+        assert_eq!(states.ranges, vec![(0..5, 1), (5..10, 2)]);
+    }
+
+    #[test]
+    fn ranged_states_coalesce_merges_equal_adjacent_segments() {
+        let mut states = RangedStates {
+            ranges: vec![(0..2, 5u32), (2..4, 5), (4..6, 9), (6..8, 9)],
+        };
+        states.coalesce();
+        assert_eq!(states.ranges, vec![(0..4, 5), (4..8, 9)]);
+    }
+
+    #[test]
+    fn ranged_states_coalesce_leaves_unequal_segments_apart() {
+        let mut states = RangedStates {
+            ranges: vec![(0..2, 1u32), (2..4, 2)],
+        };
+        states.coalesce();
+        assert_eq!(states.ranges, vec![(0..2, 1), (2..4, 2)]);
+    }
+
+    #[test]
+    fn linearize_whole_level_range_is_one_contiguous_span() {
+        let selector = TextureSelector {
+            levels: 0..4,
+            layers: 1..3,
+        };
+        assert_eq!(selector.linearize(4), vec![4..12]);
+    }
+
+    #[test]
+    fn linearize_partial_level_range_is_one_span_per_layer() {
+        let selector = TextureSelector {
+            levels: 1..3,
+            layers: 0..2,
+        };
+        assert_eq!(selector.linearize(4), vec![1..3, 5..7]);
+    }
+
+    #[test]
+    fn linearize_single_layer_out_of_range_prefix_is_still_per_layer() {
+        // Levels 2..4 of a 4-level texture don't start at 0, so even a
+        // single-layer selector can't take the whole-range fast path.
+        let selector = TextureSelector {
+            levels: 2..4,
+            layers: 3..4,
+        };
+        assert_eq!(selector.linearize(4), vec![14..16]);
+    }
+}